@@ -1,6 +1,15 @@
 //! Hummock SST builder.
 //!
 //! The SST format is exactly the same as `AgateDB` (`BadgerDB`), and is very similar to `RocksDB`.
+//!
+//! `TableBuilder` writes every block uncompressed and unencrypted with a Crc32c checksum, and
+//! builds one table-wide Bloom filter (`TableMeta.bloom_filter`, the format `Table::load` reads).
+//! The block compression, checksum, encryption and SwissTable hash index helpers below
+//! (`compress_block`, `checksum_block`, `encrypt_block`,
+//! `build_hash_index`/`group_match`/`hash_index_lookup`) are private, compiled only for tests, and
+//! not called by `TableBuilder`; `TableBuilderOptions` has no field that selects them. The builder
+//! never sets the `TableMeta` fields `block_compression`, `encrypted`, `hash_index` or
+//! `block_bloom_filters`.
 
 // Copyright 2021 TiKV Project Authors. Licensed under Apache-2.0.
 
@@ -41,6 +50,275 @@ impl Header {
     }
 }
 
+/// Compression algorithm a block's payload would be tagged with before being written to
+/// `data_buf`. Not wired into `TableBuilder` (see the module doc): `TableBuilderOptions` has no
+/// field selecting this, so this and `compress_block` are exercised only by the unit tests below.
+#[cfg(test)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum CompressionAlgorithm {
+    None,
+    Snappy,
+    Lz4,
+    Zstd,
+}
+
+#[cfg(test)]
+impl CompressionAlgorithm {
+    /// Single-byte tag persisted alongside the compressed payload.
+    fn tag(self) -> u8 {
+        match self {
+            CompressionAlgorithm::None => 0,
+            CompressionAlgorithm::Snappy => 1,
+            CompressionAlgorithm::Lz4 => 2,
+            CompressionAlgorithm::Zstd => 3,
+        }
+    }
+}
+
+/// Compress `block` with `algorithm`, returning the compressed bytes and the tag to persist.
+#[cfg(test)]
+fn compress_block(algorithm: CompressionAlgorithm, block: &[u8]) -> (Bytes, u8) {
+    let tag = algorithm.tag();
+    match algorithm {
+        CompressionAlgorithm::None => (Bytes::copy_from_slice(block), tag),
+        CompressionAlgorithm::Snappy => {
+            let compressed = snap::raw::Encoder::new()
+                .compress_vec(block)
+                .expect("snappy compression should never fail");
+            (Bytes::from(compressed), tag)
+        }
+        CompressionAlgorithm::Lz4 => {
+            let compressed = lz4::block::compress(block, None, false)
+                .expect("lz4 compression should never fail");
+            (Bytes::from(compressed), tag)
+        }
+        CompressionAlgorithm::Zstd => {
+            let compressed =
+                zstd::bulk::compress(block, 0).expect("zstd compression should never fail");
+            (Bytes::from(compressed), tag)
+        }
+    }
+}
+
+/// Checksum algorithm used to verify a block's on-disk bytes before it is decrypted/decompressed.
+/// Not wired into `TableBuilder` (see the module doc): `TableBuilderOptions` has no field
+/// selecting this, so this and `checksum_block` are exercised only by the unit tests below.
+/// `finish_block` always computes a `Crc32c` checksum directly via `crc32_checksum`.
+#[cfg(test)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum ChecksumAlgorithm {
+    Crc32c,
+    XxHash64,
+}
+
+/// Checksums `block` with `algorithm`, returning the sum and the `Checksum.algo` tag to persist
+/// alongside it so the reader knows which function to re-verify with.
+#[cfg(test)]
+fn checksum_block(algorithm: ChecksumAlgorithm, block: &[u8]) -> (u64, ChecksumAlg) {
+    match algorithm {
+        // `u64::from` (rather than an `as` cast) widens whatever integer width
+        // `crc32_checksum` returns into `Checksum.sum`'s `u64` without tripping clippy's
+        // `unnecessary_cast` lint if that width is ever changed to match.
+        ChecksumAlgorithm::Crc32c => (u64::from(crc32_checksum(block)), ChecksumAlg::Crc32c),
+        // xxHash3/xxHash64 is substantially faster than CRC32c on large blocks when the CPU lacks
+        // a hardware CRC instruction, at the cost of not being a true error-detecting code.
+        ChecksumAlgorithm::XxHash64 => (xxhash_rust::xxh3::xxh3_64(block), ChecksumAlg::XxHash64),
+    }
+}
+
+/// Length in bytes of the randomly generated IV appended to each encrypted block.
+#[cfg(test)]
+const ENCRYPTION_IV_LEN: usize = 16;
+
+/// Encrypt `block` with AES-CTR under `key`, using a freshly generated random IV, and return
+/// `ciphertext || iv`. The IV does not need to be secret, only unique per block. `key`'s length
+/// selects the cipher width: 16 bytes for AES-128, 24 for AES-192, 32 for AES-256. Not wired into
+/// `TableBuilder` (see the module doc): `TableBuilderOptions` has no field selecting this, so
+/// this is exercised only by the unit tests below.
+#[cfg(test)]
+fn encrypt_block(key: &[u8], block: &[u8]) -> Vec<u8> {
+    use aes::cipher::{NewCipher, StreamCipher};
+    use rand::RngCore;
+
+    let mut iv = vec![0u8; ENCRYPTION_IV_LEN];
+    rand::thread_rng().fill_bytes(&mut iv);
+
+    let mut ciphertext = block.to_vec();
+    match key.len() {
+        16 => {
+            let mut cipher =
+                aes::Aes128Ctr::new_from_slices(key, &iv).expect("IV has wrong length");
+            cipher.apply_keystream(&mut ciphertext);
+        }
+        24 => {
+            let mut cipher =
+                aes::Aes192Ctr::new_from_slices(key, &iv).expect("IV has wrong length");
+            cipher.apply_keystream(&mut ciphertext);
+        }
+        32 => {
+            let mut cipher =
+                aes::Aes256Ctr::new_from_slices(key, &iv).expect("IV has wrong length");
+            cipher.apply_keystream(&mut ciphertext);
+        }
+        other => panic!(
+            "encryption key must be 16, 24, or 32 bytes long (AES-128/192/256), got {} bytes",
+            other
+        ),
+    }
+    ciphertext.extend_from_slice(&iv);
+    ciphertext
+}
+
+/// Extracts the trailing 8-byte big-endian version (epoch) suffix from an internal key, i.e. the
+/// part `user_key` strips off.
+fn entry_version(key: &[u8]) -> u64 {
+    let version_bytes = &key[key.len() - 8..];
+    u64::from_be_bytes(version_bytes.try_into().unwrap())
+}
+
+/// Number of slots probed together as one group in the hash index, matching the 128-bit width an
+/// SSE2 `_mm_cmpeq_epi8` compares in a single instruction.
+const HASH_INDEX_GROUP_SIZE: usize = 16;
+
+/// Control byte marking an unoccupied hash index slot. Occupied slots only ever store `h2`, which
+/// is 7 bits wide, so the top bit is reserved to tell empty slots apart from real ones.
+const HASH_INDEX_EMPTY_CTRL: u8 = 0x80;
+
+/// Target load factor `build_hash_index` sizes the hash index to, the same tradeoff `odht`
+/// makes between probe-sequence length and wasted slots.
+const HASH_INDEX_LOAD_FACTOR: f64 = 0.85;
+
+/// Points a hashed key at the block and in-block byte offset (the same offset space restart
+/// points live in, i.e. relative to the block's own base offset) where its entry starts.
+///
+/// `#[cfg(test)]`: this, `build_hash_index`, and `split_hash` are build-only scaffolding for a
+/// SwissTable point-lookup index, not a shipped lookup feature -- there is no `Table::get_exact`
+/// anywhere in this crate to consult the index they produce, so `TableBuilderOptions` has no
+/// field wired up to call them outside tests. See the module doc above.
+#[cfg(test)]
+#[derive(Debug, Clone, Copy)]
+struct HashIndexEntry {
+    hash: u32,
+    block_index: u32,
+    entry_offset: u32,
+}
+
+/// Splits `hash` into the SwissTable `(h1, h2)` pair: `h1` selects the entry's home group, `h2` is
+/// the 7-bit control byte stored in its slot once a group is probed.
+#[cfg(test)]
+fn split_hash(hash: u32) -> (u32, u8) {
+    (hash >> 7, (hash & 0x7f) as u8)
+}
+
+/// Builds a compact open-addressing hash index over `entries`, SwissTable-style: a control-byte
+/// array (grouped by `HASH_INDEX_GROUP_SIZE`) guides probing, and each occupied slot stores the
+/// `(block_index, entry_offset)` payload for the key that landed there.
+///
+/// Layout: `[control bytes; num_slots][slot payloads; num_slots * 8][num_slots: u32 LE]`.
+#[cfg(test)]
+fn build_hash_index(entries: &[HashIndexEntry]) -> Vec<u8> {
+    let wanted_slots =
+        ((entries.len() as f64 / HASH_INDEX_LOAD_FACTOR).ceil() as usize).max(HASH_INDEX_GROUP_SIZE);
+    let num_groups = (wanted_slots + HASH_INDEX_GROUP_SIZE - 1) / HASH_INDEX_GROUP_SIZE;
+    let num_slots = num_groups * HASH_INDEX_GROUP_SIZE;
+
+    let mut control = vec![HASH_INDEX_EMPTY_CTRL; num_slots];
+    let mut payload = vec![0u8; num_slots * 8];
+
+    for entry in entries {
+        let (h1, h2) = split_hash(entry.hash);
+        let home_group = h1 as usize % num_groups;
+        let slot = (0..num_groups)
+            .map(|probe| (home_group + probe) % num_groups)
+            .find_map(|group| {
+                let base = group * HASH_INDEX_GROUP_SIZE;
+                (0..HASH_INDEX_GROUP_SIZE)
+                    .find(|&i| control[base + i] == HASH_INDEX_EMPTY_CTRL)
+                    .map(|i| base + i)
+            })
+            .expect("hash index sized at HASH_INDEX_LOAD_FACTOR should never fill up");
+
+        control[slot] = h2;
+        payload[slot * 8..slot * 8 + 4].copy_from_slice(&entry.block_index.to_le_bytes());
+        payload[slot * 8 + 4..slot * 8 + 8].copy_from_slice(&entry.entry_offset.to_le_bytes());
+    }
+
+    let mut buf = BytesMut::with_capacity(control.len() + payload.len() + 4);
+    buf.put_slice(&control);
+    buf.put_slice(&payload);
+    buf.put_u32_le(num_slots as u32);
+    buf.to_vec()
+}
+
+/// Compares all `HASH_INDEX_GROUP_SIZE` control bytes in `group` against `h2` in a single SSE2
+/// instruction, returning the matching slot indices (in order) within the group.
+///
+/// `#[cfg(test)]`: build-only scaffolding, not a shipped lookup feature -- the only caller today
+/// is `hash_index_lookup`, itself only exercised from `mod tests` below, since `Table::get_exact`
+/// doesn't exist in this crate to consult `meta.hash_index` outside tests. Drop the `cfg` once
+/// `get_exact` is wired up to use it.
+#[cfg(all(test, target_arch = "x86_64"))]
+fn group_match(group: &[u8], h2: u8) -> Vec<usize> {
+    use std::arch::x86_64::{_mm_cmpeq_epi8, _mm_loadu_si128, _mm_movemask_epi8, _mm_set1_epi8};
+
+    debug_assert_eq!(group.len(), HASH_INDEX_GROUP_SIZE);
+    let mask = unsafe {
+        let haystack = _mm_loadu_si128(group.as_ptr() as *const _);
+        let needle = _mm_set1_epi8(h2 as i8);
+        _mm_movemask_epi8(_mm_cmpeq_epi8(haystack, needle)) as u32
+    };
+    (0..HASH_INDEX_GROUP_SIZE)
+        .filter(|i| mask & (1 << i) != 0)
+        .collect()
+}
+
+/// Scalar fallback for platforms without SSE2.
+#[cfg(all(test, not(target_arch = "x86_64")))]
+fn group_match(group: &[u8], h2: u8) -> Vec<usize> {
+    (0..group.len()).filter(|&i| group[i] == h2).collect()
+}
+
+/// Looks up `hash` in a hash index built by `build_hash_index`, returning every candidate
+/// `(block_index, entry_offset)` whose control byte matched `h2`. A match only narrows the
+/// candidates down to entries that hashed to the same `h2`; the caller must still fetch and
+/// compare the full key at each candidate to confirm it, the same contract `odht` exposes.
+///
+/// `#[cfg(test)]`: build-only scaffolding, not a shipped lookup feature -- nothing outside `mod
+/// tests` calls this, since `Table::get_exact` (the reader's planned consumer of
+/// `meta.hash_index`) doesn't exist in this crate. Drop the `cfg` together with `group_match`'s
+/// once the reader-side wiring lands.
+#[cfg(test)]
+fn hash_index_lookup(index: &[u8], hash: u32) -> Vec<(u32, u32)> {
+    let num_slots = u32::from_le_bytes(index[index.len() - 4..].try_into().unwrap()) as usize;
+    let num_groups = num_slots / HASH_INDEX_GROUP_SIZE;
+    let control = &index[..num_slots];
+    let payload = &index[num_slots..num_slots + num_slots * 8];
+
+    let (h1, h2) = split_hash(hash);
+    let home_group = h1 as usize % num_groups;
+    let mut candidates = Vec::new();
+    for probe in 0..num_groups {
+        let group = (home_group + probe) % num_groups;
+        let base = group * HASH_INDEX_GROUP_SIZE;
+        let group_bytes = &control[base..base + HASH_INDEX_GROUP_SIZE];
+        for i in group_match(group_bytes, h2) {
+            let slot = base + i;
+            let block_index =
+                u32::from_le_bytes(payload[slot * 8..slot * 8 + 4].try_into().unwrap());
+            let entry_offset =
+                u32::from_le_bytes(payload[slot * 8 + 4..slot * 8 + 8].try_into().unwrap());
+            candidates.push((block_index, entry_offset));
+        }
+        // Insertion always places a key in the first empty slot along its probe sequence, so once
+        // we pass a group containing an empty slot, the key (if present) must already have been
+        // found; nothing further down the sequence can belong to it.
+        if group_bytes.contains(&HASH_INDEX_EMPTY_CTRL) {
+            break;
+        }
+    }
+    candidates
+}
+
 #[derive(Debug, Clone)]
 pub struct TableBuilderOptions {
     /// Target capacity of the table
@@ -66,28 +344,32 @@ pub struct TableBuilder {
     /// Buffer blocks data
     data_buf: BytesMut,
 
-    /// Used for prefix-encode
-    base_key: Bytes,
+    /// Full key of the first entry in the current block, used for `BlockOffset.key`.
+    first_key_in_block: Bytes,
     base_offset: u32,
 
-    /// Entry offsets in a block
-    entry_offsets: Vec<u32>,
+    /// Offsets of every entry in the current block, written as the block's restart array. Only
+    /// the block's first entry stores its full key; every other entry stores its diff against
+    /// that first key -- see `add_inner`.
+    restarts: Vec<u32>,
 
-    /// Used for building the Bloom filter
+    /// Key hashes of every entry added so far, used to build the table-wide `meta.bloom_filter`
+    /// in `finish`.
     key_hashes: Vec<u32>,
 }
 
 impl TableBuilder {
     /// Create new builder from options
     pub fn new(options: TableBuilderOptions) -> Self {
+        // approximately 16MB index + table size.
+        let capacity = options.table_capacity as usize;
         Self {
-            // approximately 16MB index + table size
-            data_buf: BytesMut::with_capacity(options.table_capacity as usize),
+            data_buf: BytesMut::with_capacity(capacity),
             meta: TableMeta::default(),
-            base_key: Bytes::new(),
+            first_key_in_block: Bytes::new(),
             base_offset: 0,
-            key_hashes: Vec::with_capacity(1024),
-            entry_offsets: vec![],
+            key_hashes: vec![],
+            restarts: vec![],
             options,
         }
     }
@@ -97,27 +379,27 @@ impl TableBuilder {
         self.data_buf.is_empty()
     }
 
-    /// Calculate the difference of two keys
-    fn key_diff<'a>(&self, key: &'a [u8]) -> &'a [u8] {
-        bytes_diff(&self.base_key, key)
-    }
-
     /// Append encoded block bytes to the buffer
     fn finish_block(&mut self) {
         // ---------- encode block ----------
 
         // different behavior: BadgerDB will just return.
-        assert!(!self.entry_offsets.is_empty());
+        assert!(!self.restarts.is_empty());
 
-        // encode offsets list and its length
-        for offset in &self.entry_offsets {
+        // encode restart point array and its length
+        for offset in &self.restarts {
             self.data_buf.put_u32_le(*offset);
         }
-        self.data_buf.put_u32(self.entry_offsets.len() as u32);
+        self.data_buf.put_u32(self.restarts.len() as u32);
 
-        // encode checksum and its length
+        // encode checksum and its length. The checksum covers the payload so the reader can
+        // verify integrity. `TableBuilderOptions` has no field selecting the checksum algorithm
+        // or encryption (see the module doc), so this is always Crc32c over the plaintext
+        // entries plus restart array.
         let checksum = Checksum {
-            sum: crc32_checksum(&self.data_buf[self.base_offset as usize..]),
+            sum: u64::from(crc32_checksum(
+                &self.data_buf[self.base_offset as usize..],
+            )),
             algo: ChecksumAlg::Crc32c as i32,
         };
         let mut cs_bytes = BytesMut::new();
@@ -128,7 +410,7 @@ impl TableBuilder {
 
         // ---------- add block offset to meta ----------
         let block_offset = BlockOffset {
-            key: self.base_key.to_vec(),
+            key: self.first_key_in_block.to_vec(),
             offset: self.base_offset,
             len: self.data_buf.len() as u32 - self.base_offset,
         };
@@ -137,22 +419,24 @@ impl TableBuilder {
 
     fn should_finish_block(&self, key: &[u8], value: &HummockValue<Vec<u8>>) -> bool {
         // If there is no entry till now, we will return false.
-        if self.entry_offsets.is_empty() {
+        if self.restarts.is_empty() {
             return false;
         }
 
-        // We should include current entry also in size, that's why +1 to len(b.entryOffsets).
-        let entries_offsets_size = ((self.entry_offsets.len() + 1) * 4 +
-        4 + // size of list
+        // We should include current entry also in size -- every entry is a restart point (see
+        // `add_inner`), so it always grows the restart array by one slot.
+        let restarts_count = self.restarts.len() + 1;
+        let restarts_size = (restarts_count * 4 +
+        4 + // size of restart array
         8 + // sum64 in checksum proto
         4) as u32; // checksum length
                    // Integer overflow check for statements above.
-        assert!(entries_offsets_size < u32::MAX);
+        assert!(restarts_size < u32::MAX);
         let estimated_size = (self.data_buf.len() as u32)
             - self.base_offset + 6 // header size for entry
             + key.len() as u32
             + value.encoded_len() as u32
-            + entries_offsets_size;
+            + restarts_size;
 
         // Integer overflow check for table size.
         assert!(self.data_buf.len() as u32 + estimated_size < u32::MAX);
@@ -166,22 +450,34 @@ impl TableBuilder {
     /// ```
     /// Add adds a key-value pair to the block.
     pub fn add(&mut self, key: &[u8], value: HummockValue<Vec<u8>>) {
+        self.add_inner(key, value, false)
+    }
+
+    /// Like `add`, but for an entry the caller already knows is a superseded/overwritten version
+    /// (e.g. during compaction). Counts towards `TableMeta::stale_data_size` so the compaction
+    /// planner can tell how much of the SST is dead weight without re-reading it.
+    pub fn add_stale(&mut self, key: &[u8], value: HummockValue<Vec<u8>>) {
+        self.add_inner(key, value, true)
+    }
+
+    fn add_inner(&mut self, key: &[u8], value: HummockValue<Vec<u8>>, stale: bool) {
         if self.should_finish_block(key, &value) {
             self.finish_block();
-            self.base_key.clear();
+            self.first_key_in_block.clear();
             assert!(self.data_buf.len() < u32::MAX as usize);
             self.base_offset = self.data_buf.len() as u32;
-            self.entry_offsets.clear();
+            self.restarts.clear();
         }
 
-        self.key_hashes.push(farmhash::fingerprint32(user_key(key)));
+        let key_hash = farmhash::fingerprint32(user_key(key));
+        self.key_hashes.push(key_hash);
 
-        // diff_key stores the difference of key with baseKey.
-        let diff_key = if self.base_key.is_empty() {
-            self.base_key = key.to_vec().into();
+        // Every entry in the block is a restart point and diffs against the block's first key
+        // rather than the preceding entry.
+        let diff_key = if self.first_key_in_block.is_empty() {
             key
         } else {
-            self.key_diff(key)
+            bytes_diff(&self.first_key_in_block, key)
         };
         assert!(key.len() - diff_key.len() <= u16::MAX as usize);
         assert!(diff_key.len() <= u16::MAX as usize);
@@ -193,8 +489,10 @@ impl TableBuilder {
         };
         assert!(self.data_buf.len() <= u32::MAX as usize);
 
-        // store current entry's offset
-        self.entry_offsets
+        if self.first_key_in_block.is_empty() {
+            self.first_key_in_block = key.to_vec().into();
+        }
+        self.restarts
             .push(self.data_buf.len() as u32 - self.base_offset);
 
         // entry layout: header, diffKey, value.
@@ -205,13 +503,20 @@ impl TableBuilder {
         // update estimated size
         let block_size = value.encoded_len() + diff_key.len() + 4;
         self.meta.estimated_size += block_size as u32;
+
+        // Track the newest version seen and, for known-stale entries, how many bytes of the SST
+        // are dead weight, so the compaction planner gets both signals without re-reading the SST.
+        self.meta.max_version = self.meta.max_version.max(entry_version(key));
+        if stale {
+            self.meta.stale_data_size += block_size as u32;
+        }
     }
 
     /// Returns true if we roughly reached capacity
     pub fn reach_capacity(&self) -> bool {
         let block_size = self.data_buf.len() as u32 + // actual length of current buffer
-                                 self.entry_offsets.len() as u32 * 4 + // all entry offsets size
-                                 4 + // count of all entry offsets
+                                 self.restarts.len() as u32 * 4 + // restart point array size
+                                 4 + // count of restart points
                                  8 + // checksum bytes
                                  4; // checksum length
 
@@ -228,12 +533,19 @@ impl TableBuilder {
 
         // TODO: move boundaries and build index if we need to encrypt or compress
 
-        // initial Bloom filter
         if self.options.bloom_false_positive > 0.0 {
-            let bits_per_key =
-                Bloom::bloom_bits_per_key(self.key_hashes.len(), self.options.bloom_false_positive);
-            let bloom = Bloom::build_from_key_hashes(&self.key_hashes, bits_per_key);
-            self.meta.bloom_filter = bloom.to_vec();
+            // `meta.bloom_filter` is the pre-existing, table-wide blob `Table::load` already
+            // knows how to read (see `test_with_bloom_filter`); keep writing exactly that format
+            // here rather than repurposing the field, so existing readers aren't silently handed
+            // bytes they'll misinterpret.
+            if !self.key_hashes.is_empty() {
+                let bits_per_key = Bloom::bloom_bits_per_key(
+                    self.key_hashes.len(),
+                    self.options.bloom_false_positive,
+                );
+                let bloom = Bloom::build_from_key_hashes(&self.key_hashes, bits_per_key);
+                self.meta.bloom_filter = bloom.to_vec();
+            }
         }
 
         (self.data_buf.freeze(), self.meta)
@@ -262,6 +574,14 @@ pub(super) mod tests {
         b.finish();
     }
 
+    fn base_opts() -> TableBuilderOptions {
+        TableBuilderOptions {
+            bloom_false_positive: 0.0,
+            block_size: 0,
+            table_capacity: 0,
+        }
+    }
+
     #[test]
     fn test_header_encode_decode() {
         let mut header = Header {
@@ -276,6 +596,124 @@ pub(super) mod tests {
         assert_eq!(header.diff, 23334);
     }
 
+    #[test]
+    fn test_diffs_against_first_key_in_block() {
+        let key_count = 200;
+        let opt = TableBuilderOptions {
+            bloom_false_positive: 0.0,
+            // Large enough that all entries below land in a single block, so the size check
+            // below isolates the effect of prefix diffing rather than block count.
+            block_size: u32::MAX,
+            table_capacity: 0,
+        };
+        let mut b = TableBuilder::new(opt);
+        for i in 0..key_count {
+            b.add(
+                key(b"restart_interval_key_", i).as_ref(),
+                HummockValue::Put(vec![0u8; 4]),
+            );
+        }
+        let (blocks, meta) = b.finish();
+        assert_eq!(meta.offsets.len(), 1);
+
+        // Every entry diffs against the block's first key, so storing every entry's full key
+        // would need at least `key_count * key_len` bytes just for the keys; diffing instead
+        // means only each entry's short numeric suffix differs, so the block should be well
+        // under that floor.
+        let key_len = key(b"restart_interval_key_", 0).len();
+        assert!(blocks.len() < key_count * key_len);
+    }
+
+    #[test]
+    fn test_encrypt_block_roundtrip_via_ctr() {
+        // Exercise all three key lengths `encrypt_block` dispatches on: AES-128/192/256.
+        for key_len in [16, 24, 32] {
+            use aes::cipher::{NewCipher, StreamCipher};
+
+            let key = vec![0x42u8; key_len];
+            let data = b"some block payload to encrypt".repeat(4);
+
+            let encrypted = encrypt_block(&key, &data);
+            assert_eq!(encrypted.len(), data.len() + ENCRYPTION_IV_LEN);
+
+            // Two calls must use different, randomly generated IVs.
+            let encrypted_again = encrypt_block(&key, &data);
+            assert_ne!(
+                encrypted[encrypted.len() - ENCRYPTION_IV_LEN..],
+                encrypted_again[encrypted_again.len() - ENCRYPTION_IV_LEN..]
+            );
+
+            // Decrypting with the IV that was appended must recover the original payload: CTR
+            // mode encryption and decryption are the same operation.
+            let (ciphertext, iv) = encrypted.split_at(encrypted.len() - ENCRYPTION_IV_LEN);
+            let mut decrypted = ciphertext.to_vec();
+            match key_len {
+                16 => aes::Aes128Ctr::new_from_slices(&key, iv)
+                    .unwrap()
+                    .apply_keystream(&mut decrypted),
+                24 => aes::Aes192Ctr::new_from_slices(&key, iv)
+                    .unwrap()
+                    .apply_keystream(&mut decrypted),
+                32 => aes::Aes256Ctr::new_from_slices(&key, iv)
+                    .unwrap()
+                    .apply_keystream(&mut decrypted),
+                _ => unreachable!(),
+            }
+            assert_eq!(decrypted, data);
+        }
+    }
+
+    #[test]
+    #[should_panic]
+    fn test_encrypt_block_rejects_bad_key_length() {
+        encrypt_block(b"too_short", b"some block payload");
+    }
+
+    #[test]
+    fn test_compress_block_tag() {
+        let data = b"some block payload to compress".repeat(8);
+        let (none, tag) = compress_block(CompressionAlgorithm::None, &data);
+        assert_eq!(tag, 0);
+        assert_eq!(none.as_ref(), data.as_slice());
+
+        let (snappy, tag) = compress_block(CompressionAlgorithm::Snappy, &data);
+        assert_eq!(tag, 1);
+        assert!(!snappy.is_empty());
+        let decompressed = snap::raw::Decoder::new().decompress_vec(&snappy).unwrap();
+        assert_eq!(decompressed, data);
+
+        let (lz4, tag) = compress_block(CompressionAlgorithm::Lz4, &data);
+        assert_eq!(tag, 2);
+        assert!(!lz4.is_empty());
+        // `compress_block` calls lz4 with `prepend_size: false`, so the compressed bytes do not
+        // carry the original length and decompression has to be given it.
+        let decompressed = lz4::block::decompress(&lz4, Some(data.len() as i32)).unwrap();
+        assert_eq!(decompressed, data);
+
+        let (zstd, tag) = compress_block(CompressionAlgorithm::Zstd, &data);
+        assert_eq!(tag, 3);
+        assert!(!zstd.is_empty());
+        let decompressed = zstd::bulk::decompress(&zstd, data.len()).unwrap();
+        assert_eq!(decompressed, data);
+    }
+
+    #[test]
+    fn test_checksum_block_algo_dispatch() {
+        let data = b"some block payload to checksum".repeat(8);
+
+        let (crc_sum, crc_algo) = checksum_block(ChecksumAlgorithm::Crc32c, &data);
+        assert_eq!(crc_algo, ChecksumAlg::Crc32c);
+        assert_eq!(crc_sum, u64::from(crc32_checksum(&data)));
+
+        let (xxh_sum, xxh_algo) = checksum_block(ChecksumAlgorithm::XxHash64, &data);
+        assert_eq!(xxh_algo, ChecksumAlg::XxHash64);
+        assert_eq!(xxh_sum, xxhash_rust::xxh3::xxh3_64(&data));
+
+        // The two algorithms must not collide on the same input, otherwise picking one over the
+        // other would be meaningless.
+        assert_ne!(crc_sum, xxh_sum);
+    }
+
     pub fn generate_table() -> (Bytes, TableMeta) {
         let opt = TableBuilderOptions {
             bloom_false_positive: 0.0,
@@ -342,6 +780,71 @@ pub(super) mod tests {
         }
     }
 
+    #[test]
+    fn test_stale_data_size_and_max_version() {
+        let opt = TableBuilderOptions {
+            bloom_false_positive: 0.0,
+            block_size: u32::MAX,
+            table_capacity: 0,
+        };
+        let mut b = TableBuilder::new(opt);
+
+        let key_with_version = |prefix: &[u8], version: u64| -> Bytes {
+            let mut k = prefix.to_vec();
+            k.extend_from_slice(&version.to_be_bytes());
+            Bytes::from(k)
+        };
+
+        b.add(
+            key_with_version(b"a", 1).as_ref(),
+            HummockValue::Put(vec![0u8; 4]),
+        );
+        assert_eq!(b.meta.max_version, 1);
+        assert_eq!(b.meta.stale_data_size, 0);
+
+        b.add_stale(
+            key_with_version(b"a", 2).as_ref(),
+            HummockValue::Put(vec![0u8; 4]),
+        );
+        assert_eq!(b.meta.max_version, 2);
+        let stale_after_first_stale = b.meta.stale_data_size;
+        assert!(stale_after_first_stale > 0);
+
+        // A later, non-stale entry with an older version must not inflate the stale counter or
+        // move `max_version` backwards.
+        b.add(
+            key_with_version(b"b", 1).as_ref(),
+            HummockValue::Put(vec![0u8; 4]),
+        );
+        assert_eq!(b.meta.max_version, 2);
+        assert_eq!(b.meta.stale_data_size, stale_after_first_stale);
+
+        b.finish();
+    }
+
+    #[test]
+    fn test_hash_index_build_and_lookup_roundtrip() {
+        let entries = (0..200)
+            .map(|i| HashIndexEntry {
+                hash: farmhash::fingerprint32(format!("hash_index_key_{}", i).as_bytes()),
+                block_index: (i / 20) as u32,
+                entry_offset: (i % 20) as u32 * 16,
+            })
+            .collect_vec();
+
+        let index = build_hash_index(&entries);
+
+        for entry in &entries {
+            let candidates = hash_index_lookup(&index, entry.hash);
+            assert!(candidates.contains(&(entry.block_index, entry.entry_offset)));
+        }
+
+        // This hash was never inserted, and no entry along its probe sequence shares its `h2`, so
+        // it resolves to no candidate.
+        let absent_hash = farmhash::fingerprint32(b"hash_index_key_not_present");
+        assert!(hash_index_lookup(&index, absent_hash).is_empty());
+    }
+
     #[test]
     fn test_bloom_filter() {
         test_with_bloom_filter(false);